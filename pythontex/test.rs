@@ -2,20 +2,282 @@
     #![allow(dead_code)]
     
     mod rust_tex_utils {
-    use std::{fmt, collections};
+    use std::{fmt, collections, env, fs, io};
     use std::io::prelude::*;
+    use std::path::{Path, PathBuf};
+    use std::time::UNIX_EPOCH;
+    use std::os::unix::io::RawFd;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    extern "C" {
+        fn pipe(fds: *mut i32) -> i32;
+        fn dup(fd: i32) -> i32;
+        fn dup2(oldfd: i32, newfd: i32) -> i32;
+        fn close(fd: i32) -> i32;
+        fn read(fd: i32, buf: *mut u8, count: usize) -> isize;
+    }
+
+    // Redirects a std fd through a pipe into a buffer a reader thread drains.
+    struct FdCapture {
+        fd: i32,
+        saved: RawFd,
+        write_end: RawFd,
+        buffer: Arc<Mutex<Vec<u8>>>,
+        reader: Option<thread::JoinHandle<()>>,
+    }
+
+    // Lets a panic hook drain a capture without borrowing RustTeXUtils.
+    type CaptureSlot = Arc<Mutex<Option<FdCapture>>>;
+    static ACTIVE_CAPTURES: Mutex<Vec<CaptureSlot>> = Mutex::new(Vec::new());
+
+    fn drain_slot(slot: &CaptureSlot) -> Vec<u8> {
+        slot.lock().unwrap().take().map(FdCapture::stop).unwrap_or_default()
+    }
+
+    // Drains any captures normal execution left behind after a panic.
+    pub fn recover_captures() -> Vec<u8> {
+        let slots = ACTIVE_CAPTURES.lock().unwrap().clone();
+        slots.iter().flat_map(drain_slot).collect()
+    }
+
+    // Writes captured output into the PRINT stream for `instance`.
+    pub fn emit_to_document(instance: &str, captured: &[u8]) {
+        if captured.is_empty() {
+            return;
+        }
+        let text = String::from_utf8_lossy(captured);
+        println!("=>PYTHONTEX:PRINT#{}#", instance);
+        print!("{}", text);
+        println!("=>PYTHONTEX:PRINT#{}#", instance);
+    }
+
+    // Maps a panic's generated-file line back to the `.tex` source line.
+    pub fn panicked_line(doc_line: &str, body_start_line: u32, panic_line: u32) -> u32 {
+        doc_line.parse::<u32>().unwrap_or(0) + panic_line.saturating_sub(body_start_line)
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn panicked_line_offsets_from_body_start() {
+            assert_eq!(panicked_line("1000", 50, 50), 1000);
+            assert_eq!(panicked_line("1000", 50, 63), 1013);
+        }
+    }
+
+    impl FdCapture {
+        fn start(fd: i32) -> CaptureSlot {
+            let mut fds: [i32; 2] = [0, 0];
+            if unsafe { pipe(fds.as_mut_ptr()) } != 0 {
+                panic!("Could not create a pipe to capture fd {}", fd);
+            }
+            let (read_end, write_end) = (fds[0], fds[1]);
+            let saved = unsafe { dup(fd) };
+            unsafe { dup2(write_end, fd) };
+            let buffer = Arc::new(Mutex::new(Vec::new()));
+            let reader_buffer = buffer.clone();
+            let reader = thread::spawn(move || {
+                let mut chunk = [0u8; 4096];
+                loop {
+                    let n = unsafe { read(read_end, chunk.as_mut_ptr(), chunk.len()) };
+                    if n <= 0 {
+                        break;
+                    }
+                    reader_buffer.lock().unwrap().extend_from_slice(&chunk[..n as usize]);
+                }
+                unsafe { close(read_end) };
+            });
+            let slot: CaptureSlot = Arc::new(Mutex::new(Some(FdCapture { fd, saved, write_end, buffer, reader: Some(reader) })));
+            ACTIVE_CAPTURES.lock().unwrap().push(slot.clone());
+            slot
+        }
+
+        // Restores the original fd and returns whatever was captured.
+        fn stop(mut self) -> Vec<u8> {
+            match self.fd {
+                1 => { io::stdout().flush().ok(); }
+                2 => { io::stderr().flush().ok(); }
+                _ => {}
+            }
+            unsafe {
+                dup2(self.saved, self.fd);
+                close(self.write_end);
+                close(self.saved);
+            }
+            if let Some(reader) = self.reader.take() {
+                reader.join().ok();
+            }
+            let mut buffer = self.buffer.lock().unwrap();
+            std::mem::take(&mut *buffer)
+        }
+    }
+
     pub struct RustTeXUtils {
         formatter_: Box<FnMut(&fmt::Display) -> String>,
         before_: Box<FnMut()>,
         after_: Box<FnMut()>,
-        dependencies: Vec<String>,
-        created: Vec<String>,
+        dependencies: Vec<PathBuf>,
+        created: Vec<PathBuf>,
         command_: &'static str,
-        context_: collections::HashMap<&'static str, &'static str>,
-        args_: collections::HashMap<&'static str, &'static str>,
+        context_: collections::HashMap<String, String>,
+        args_: collections::HashMap<String, String>,
         instance_: &'static str,
         line_: &'static str,
+        capture_stdout_: bool,
+        capture_stderr_: bool,
+        stdout_capture_: Option<CaptureSlot>,
+        stderr_capture_: Option<CaptureSlot>,
+    }
+
+    // Escapes LaTeX specials so a value can be spliced into the document.
+    pub fn latex_escape_formatter(x: &fmt::Display) -> String {
+        let s = format!("{}", x);
+        let mut out: Vec<u8> = Vec::with_capacity(s.len());
+        for &b in s.as_bytes() {
+            match b {
+                b'\\' => out.extend_from_slice(b"\\textbackslash{}"),
+                b'{' => out.extend_from_slice(b"\\{"),
+                b'}' => out.extend_from_slice(b"\\}"),
+                b'$' => out.extend_from_slice(b"\\$"),
+                b'%' => out.extend_from_slice(b"\\%"),
+                b'#' => out.extend_from_slice(b"\\#"),
+                b'&' => out.extend_from_slice(b"\\&"),
+                b'_' => out.extend_from_slice(b"\\_"),
+                b'^' => out.extend_from_slice(b"\\textasciicircum{}"),
+                b'~' => out.extend_from_slice(b"\\textasciitilde{}"),
+                _ => out.push(b),
+            }
+        }
+        String::from_utf8(out).expect("formatted value must remain valid UTF-8")
+    }
+
+    #[cfg(test)]
+    mod latex_escape_formatter_tests {
+        use super::*;
+
+        #[test]
+        fn escapes_each_special_character() {
+            assert_eq!(latex_escape_formatter(&"a\\b{c}d$e%f#g&h_i^j~k"),
+                "a\\textbackslash{}b\\{c\\}d\\$e\\%f\\#g\\&h\\_i\\textasciicircum{}j\\textasciitilde{}k");
+        }
+
+        #[test]
+        fn leaves_plain_text_untouched() {
+            assert_eq!(latex_escape_formatter(&"plain text 123"), "plain text 123");
+        }
+    }
+
+    // State-machine parser: honors `"..."`/`{...}` quoting and `\`-escapes.
+    fn parse_map(kvs: &str) -> collections::HashMap<String, String> {
+        let mut result = collections::HashMap::new();
+        let mut key = String::new();
+        let mut value = String::new();
+        let mut in_value = false;
+        let mut depth: u32 = 0;
+        let mut in_quote = false;
+        let mut escape = false;
+
+        macro_rules! push {
+            ($c:expr) => {
+                if in_value { value.push($c) } else { key.push($c) }
+            };
+        }
+        macro_rules! finalize {
+            () => {{
+                let k = key.trim();
+                let v = value.trim();
+                if !k.is_empty() || !v.is_empty() {
+                    if !in_value {
+                        panic!("Error parsing supposed key-value pair ({})", k);
+                    }
+                    result.insert(k.to_string(), v.to_string());
+                }
+                key.clear();
+                value.clear();
+            }};
+        }
+
+        for c in kvs.chars() {
+            if escape {
+                push!(c);
+                escape = false;
+                continue;
+            }
+            if c == '\\' {
+                escape = true;
+                continue;
+            }
+            if in_quote {
+                if c == '"' {
+                    in_quote = false;
+                } else {
+                    push!(c);
+                }
+                continue;
+            }
+            if depth > 0 {
+                match c {
+                    '{' => { depth += 1; push!(c); }
+                    '}' => {
+                        depth -= 1;
+                        if depth > 0 { push!(c); }
+                    }
+                    _ => push!(c),
+                }
+                continue;
+            }
+            match c {
+                '"' => in_quote = true,
+                '{' => depth = 1,
+                '=' if !in_value => in_value = true,
+                ',' => { finalize!(); in_value = false; }
+                _ => push!(c),
+            }
+        }
+        finalize!();
+        result
+    }
+
+    #[cfg(test)]
+    mod parse_map_tests {
+        use super::*;
+
+        #[test]
+        fn parses_plain_pairs() {
+            let m = parse_map("a=1,b=2");
+            assert_eq!(m.get("a").map(String::as_str), Some("1"));
+            assert_eq!(m.get("b").map(String::as_str), Some("2"));
+        }
+
+        #[test]
+        fn honors_quotes_and_braces_around_commas_and_equals() {
+            let m = parse_map(r#"label="a=b, c",width={3cm, 4cm}"#);
+            assert_eq!(m.get("label").map(String::as_str), Some("a=b, c"));
+            assert_eq!(m.get("width").map(String::as_str), Some("3cm, 4cm"));
+        }
+
+        #[test]
+        fn honors_nested_braces() {
+            let m = parse_map("x={{nested}, braces}");
+            assert_eq!(m.get("x").map(String::as_str), Some("{nested}, braces"));
+        }
+
+        #[test]
+        fn honors_escaped_separators() {
+            let m = parse_map(r"path=/tmp/a\,b,plain=1");
+            assert_eq!(m.get("path").map(String::as_str), Some("/tmp/a,b"));
+            assert_eq!(m.get("plain").map(String::as_str), Some("1"));
+        }
+
+        #[test]
+        fn empty_input_yields_empty_map() {
+            assert!(parse_map("").is_empty());
+        }
     }
+
     impl RustTeXUtils {
         pub fn new() -> Self {
             RustTeXUtils {
@@ -29,6 +291,51 @@
                 args_: collections::HashMap::new(),
                 instance_: "",
                 line_: "",
+                capture_stdout_: false,
+                capture_stderr_: false,
+                stdout_capture_: None,
+                stderr_capture_: None,
+            }
+        }
+
+        // Opts a chunk into having stdout/stderr captured into the document.
+        // Starts capturing right away, since chunk code only runs after
+        // `before()` already decided whether to do so.
+        pub fn stdout_to_document(&mut self, capture_stderr: bool) {
+            if !self.capture_stdout_ {
+                self.capture_stdout_ = true;
+                self.stdout_capture_ = Some(FdCapture::start(1));
+            }
+            if capture_stderr && !self.capture_stderr_ {
+                self.capture_stderr_ = true;
+                self.stderr_capture_ = Some(FdCapture::start(2));
+            }
+        }
+
+        fn stop_capture(&mut self) -> Vec<u8> {
+            let mut captured = Vec::new();
+            if let Some(slot) = self.stdout_capture_.take() {
+                captured.extend(drain_slot(&slot));
+            }
+            if let Some(slot) = self.stderr_capture_.take() {
+                captured.extend(drain_slot(&slot));
+            }
+            captured
+        }
+
+        fn emit_captured(&self, captured: Vec<u8>) {
+            emit_to_document(self.instance_, &captured);
+        }
+
+        // Flushes captured output so far, then resumes capturing.
+        pub fn flush_print(&mut self) {
+            let captured = self.stop_capture();
+            self.emit_captured(captured);
+            if self.capture_stdout_ {
+                self.stdout_capture_ = Some(FdCapture::start(1));
+            }
+            if self.capture_stderr_ {
+                self.stderr_capture_ = Some(FdCapture::start(2));
             }
         }
         
@@ -41,37 +348,58 @@
         }
         
         pub fn before(&mut self) {
+            if self.capture_stdout_ {
+                self.stdout_capture_ = Some(FdCapture::start(1));
+            }
+            if self.capture_stderr_ {
+                self.stderr_capture_ = Some(FdCapture::start(2));
+            }
             (*self.before_)();
         }
-        
+
         pub fn set_before<F: FnMut() + 'static>(&mut self, f: F) {
             self.before_ = Box::new(f);
         }
-        
+
         pub fn after(&mut self) {
             (*self.after_)();
+            let captured = self.stop_capture();
+            self.emit_captured(captured);
         }
         
         pub fn set_after<F: FnMut() + 'static>(&mut self, f: F) {
             self.after_ = Box::new(f);
         }
         
-        pub fn add_dependencies<SS: IntoIterator>(&mut self, deps: SS) where SS::Item: Into<String> {
-            self.dependencies.append(&mut deps.into_iter().map(|x| x.into()).collect());
+        pub fn add_dependencies<SS: IntoIterator>(&mut self, deps: SS) where SS::Item: AsRef<Path> {
+            self.dependencies.append(&mut deps.into_iter().map(|x| x.as_ref().to_path_buf()).collect());
         }
-        
-        pub fn add_created<SS: IntoIterator>(&mut self, crts: SS) where SS::Item: Into<String> {
-            self.created.append(&mut crts.into_iter().map(|x| x.into()).collect());
+
+        pub fn add_created<SS: IntoIterator>(&mut self, crts: SS) where SS::Item: AsRef<Path> {
+            self.created.append(&mut crts.into_iter().map(|x| x.as_ref().to_path_buf()).collect());
         }
-        
+
+        // Resolves against the cwd so the recorded path and its mtime match,
+        // even when the path doesn't exist (so canonicalize can't resolve it).
+        fn path_mtime_line(p: &Path) -> String {
+            let resolved = fs::canonicalize(p).unwrap_or_else(|_| {
+                env::current_dir().map(|cwd| cwd.join(p)).unwrap_or_else(|_| p.to_path_buf())
+            });
+            let mtime = fs::metadata(&resolved).and_then(|m| m.modified()).ok()
+                .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                .map(|d| format!("{}.{:09}", d.as_secs(), d.subsec_nanos()))
+                .unwrap_or_else(|| "0".to_string());
+            format!("{}\t{}", resolved.display(), mtime)
+        }
+
         pub fn cleanup(self) {
             println!("{}", "");
-            for x in self.dependencies {
-                println!("{}", x);
+            for x in &self.dependencies {
+                println!("{}", Self::path_mtime_line(x));
             }
             println!("{}", "");
-            for x in self.created {
-                println!("{}", x);
+            for x in &self.created {
+                println!("{}", Self::path_mtime_line(x));
             }
         }
         
@@ -88,12 +416,6 @@
         }
         
         pub fn setup_wrapper(mut self, cmd: &'static str, cxt: &'static str, ags: &'static str, ist: &'static str, lne: &'static str) -> Self {
-            fn parse_map(kvs: &'static str) -> collections::HashMap<&'static str, &'static str> {
-                kvs.split(',').filter(|s| !s.is_empty()).map(|kv| {
-                    let (k, v) = kv.split_at(kv.find('=').expect(&format!("Error parsing supposed key-value pair ({})", kv)));
-                    (k.trim(), v[1..].trim())
-                }).collect()
-            }
             self.command_ = cmd;
             self.context_ = parse_map(cxt);
             self.args_ = parse_map(ags);
@@ -106,11 +428,11 @@
             self.command_
         }
         
-        pub fn context(&self) -> &collections::HashMap<&'static str, &'static str> {
+        pub fn context(&self) -> &collections::HashMap<String, String> {
             &self.context_
         }
-        
-        pub fn args(&self) -> &collections::HashMap<&'static str, &'static str> {
+
+        pub fn args(&self) -> &collections::HashMap<String, String> {
             &self.args_
         }
         
@@ -124,25 +446,56 @@
     }
     }
     
-    use std::{io, env, ffi};
+    use std::{io, env, ffi, panic, process};
     use std::io::prelude::*;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicU32, Ordering};
     #[allow(unused_mut)]
     fn main() {
     let mut rstex = rust_tex_utils::RustTeXUtils::new();
     if env::set_current_dir(ffi::OsString::from("/".to_string())).is_err() && env::args().all(|x| x != "--manual") {
         panic!("Could not change to the specified working directory (/)");
     }
-    
 
-    
+
+
     let mut rstex = rstex.setup_wrapper("", "", "", "", "");
     println!("");
     writeln!(io::stderr(), "").unwrap();
+
+    let doc_line: &'static str = rstex.line();
+    let hook_instance: &'static str = rstex.instance();
+    // Set next to rstex.before() below, so it tracks the chunk body's start.
+    let body_start_line = Arc::new(AtomicU32::new(0));
+    let hook_body_start_line = body_start_line.clone();
+    panic::set_hook(Box::new(move |info| {
+        let payload = info.payload().downcast_ref::<&str>().map(|s| s.to_string())
+            .or_else(|| info.payload().downcast_ref::<String>().cloned())
+            .unwrap_or_else(|| "unknown panic payload".to_string());
+        let panic_line = info.location().map(|l| l.line()).unwrap_or(0);
+        let reported_line = rust_tex_utils::panicked_line(doc_line, hook_body_start_line.load(Ordering::SeqCst), panic_line);
+        let backtrace = std::backtrace::Backtrace::force_capture();
+        // Restores the real fd before we write to it, so this isn't swallowed.
+        let captured = rust_tex_utils::recover_captures();
+        rust_tex_utils::emit_to_document(hook_instance, &captured);
+        writeln!(io::stderr(), "=>PYTHONTEX:STDERR#0#r#").unwrap();
+        writeln!(io::stderr(), "Rust chunk panicked on line {}: {}", reported_line, payload).unwrap();
+        writeln!(io::stderr(), "{}", backtrace).unwrap();
+        io::stderr().flush().unwrap();
+    }));
+
+    let result = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+    body_start_line.store(line!() + 2, Ordering::SeqCst);
     rstex.before();
-    
 
-    
+
+
     rstex.after();
-    
-    rstex.cleanup()
+    }));
+
+    rstex.cleanup();
+
+    if result.is_err() {
+        process::exit(1);
+    }
     }